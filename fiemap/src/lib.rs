@@ -1,26 +1,67 @@
 use std::fmt;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::{Error, ErrorKind, Result};
+use std::mem::size_of;
 use std::os::fd::FromRawFd;
 use std::os::raw::{c_int, c_ulong};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
+mod snapshot;
+pub use snapshot::{diff, ExtentChange, FileExtentMap};
+
 const FS_IOC_FIEMAP: c_ulong = 0xC020660B;
-const PAGESIZE: usize = 8;
+
+/// Legacy `FIBMAP` ioctl, used as a fallback when `FS_IOC_FIEMAP` isn't
+/// supported. Takes a single `c_int` logical block number in/out and
+/// returns the physical block number, or `0` for a hole.
+const FIBMAP: c_ulong = 0x00000001;
+
+/// `ioctl` returns this when the underlying filesystem/kernel doesn't
+/// implement the ioctl at all.
+const ENOTTY: i32 = 25;
+/// `ioctl` returns this when the filesystem recognizes `FS_IOC_FIEMAP`
+/// but doesn't support it (e.g. some FUSE/network filesystems).
+const EOPNOTSUPP: i32 = 95;
+
+/// `open` returns this when the caller lacks the ownership/`CAP_FOWNER`
+/// required for `O_NOATIME`.
+const EPERM: i32 = 1;
+
+/// Linux `open(2)` flag: don't follow a trailing symlink in the path.
+const O_NOFOLLOW: i32 = 0o400000;
+/// Linux `open(2)` flag: don't update the file's access time on read.
+/// Requires the caller to own the file or hold `CAP_FOWNER`.
+const O_NOATIME: i32 = 0o1000000;
+
+/// Number of extents requested per `ioctl` call when no explicit
+/// [`FiemapOptions::extent_batch_size`] is set. Kept small for
+/// compatibility with the historical stack-allocated behavior.
+const DEFAULT_EXTENT_BATCH_SIZE: u32 = 8;
 
 unsafe extern "C" {
     fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
 }
 
+#[derive(Debug)]
+enum Backend {
+    Fiemap(C_fiemap),
+    /// Extents synthesized from the legacy `FIBMAP` ioctl, one block at
+    /// a time, coalesced into runs. See [`Fiemap::used_fibmap_fallback`].
+    Fibmap(Vec<FiemapExtent>),
+}
+
 #[derive(Debug)]
 pub struct Fiemap {
     _file: File,
     fd: c_int,
-    fiemap: C_fiemap,
+    backend: Backend,
     cur_idx: usize,
     size: u32,
     ended: bool,
+    start: u64,
+    end: u64,
 }
 
 /// Get fiemap for the path and return an iterator of extents.
@@ -30,6 +71,91 @@ pub fn fiemap<P: AsRef<Path>>(filepath: P) -> Result<Fiemap> {
     Fiemap::new_from_path(filepath)
 }
 
+bitflags::bitflags! {
+  #[derive(Copy, Clone, Debug)]
+  pub struct FiemapFlags: u32 {
+    #[doc = "Sync file data before map."]
+    const SYNC  = 0x00000001;
+    #[doc = "Map extended attribute tree."]
+    const XATTR = 0x00000002;
+  }
+}
+
+bitflags::bitflags! {
+  #[derive(Copy, Clone, Debug)]
+  pub struct OpenFlags: u32 {
+    #[doc = "Don't follow a trailing symlink when opening the path."]
+    const NOFOLLOW = 0x00000001;
+    #[doc = "Don't update the file's access time on read. Requires \
+             ownership of the file or CAP_FOWNER; silently ignored \
+             (rather than failing the open) if the caller lacks it."]
+    const NOATIME  = 0x00000002;
+  }
+}
+
+/// Builder for the options passed to the `FS_IOC_FIEMAP` ioctl.
+///
+/// By default the whole file is mapped with no flags set, which matches
+/// the behavior of [`Fiemap::new`]/[`Fiemap::new_from_path`] prior to
+/// this builder's introduction.
+#[derive(Debug, Clone, Copy)]
+pub struct FiemapOptions {
+    flags: FiemapFlags,
+    start: u64,
+    length: u64,
+    extent_batch_size: u32,
+}
+
+impl Default for FiemapOptions {
+    fn default() -> Self {
+        Self {
+            flags: FiemapFlags::empty(),
+            start: 0,
+            length: u64::MAX,
+            extent_batch_size: DEFAULT_EXTENT_BATCH_SIZE,
+        }
+    }
+}
+
+impl FiemapOptions {
+    /// Creates a new [`Self`] with the default behavior: map the whole
+    /// file starting at offset `0` with no flags set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the `fm_flags` passed to the kernel, see [`FiemapFlags`].
+    pub fn flags(mut self, flags: FiemapFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Sets the logical start offset, in bytes, to map from.
+    pub fn start(mut self, start: u64) -> Self {
+        self.start = start;
+        self
+    }
+
+    /// Sets the logical length, in bytes, to map.
+    pub fn length(mut self, length: u64) -> Self {
+        self.length = length;
+        self
+    }
+
+    /// Sets how many extents are requested per `ioctl` call. Higher
+    /// values trade a larger one-time heap allocation for fewer syscalls
+    /// on heavily fragmented files. Defaults to 8.
+    ///
+    /// Clamped to at least `1`: the kernel treats `fm_extent_count == 0`
+    /// as a special "count only" query that leaves the extent array
+    /// untouched while still reporting the real (nonzero) extent count,
+    /// which would otherwise read out of bounds of our empty buffer.
+    pub fn extent_batch_size(mut self, extent_batch_size: u32) -> Self {
+        self.extent_batch_size = extent_batch_size.max(1);
+        self
+    }
+}
+
 impl Fiemap {
     /// Creates a new [`Self`] from any type that implements [`AsFd`].
     ///
@@ -37,16 +163,30 @@ impl Fiemap {
     /// the [`Self`] instance, as the file descriptor will be closed
     /// after the instance of [`Self`] is dropped.
     pub fn new(fd: impl AsRawFd) -> Self {
+        Self::new_with_options(fd, FiemapOptions::default())
+    }
+
+    /// Creates a new [`Self`] from any type that implements [`AsRawFd`],
+    /// using the given [`FiemapOptions`] to control `fm_flags` and the
+    /// logical range that gets mapped.
+    pub fn new_with_options(fd: impl AsRawFd, options: FiemapOptions) -> Self {
         let raw_fd = fd.as_raw_fd();
         let file = unsafe { File::from_raw_fd(raw_fd) };
 
         Self {
             _file: file,
             fd: raw_fd,
-            fiemap: C_fiemap::new(),
+            backend: Backend::Fiemap(C_fiemap::new(
+                options.flags,
+                options.start,
+                options.length,
+                options.extent_batch_size,
+            )),
             cur_idx: 0,
             size: 0,
             ended: false,
+            start: options.start,
+            end: options.start.saturating_add(options.length),
         }
     }
 
@@ -58,29 +198,200 @@ impl Fiemap {
         Ok(Self::new(file))
     }
 
-    fn get_extents(&mut self) -> Result<()> {
-        let req = &mut self.fiemap;
-        if self.size != 0 {
-            let last = req.fm_extents[self.size as usize - 1];
-            req.fm_start = last.fe_logical + last.fe_length;
+    /// Creates a new [`Self`] from a file path, opening the file in
+    /// read-only mode and using the given [`FiemapOptions`]. See
+    /// [`std::fs::File::open`] and [`Self::new_with_options`].
+    pub fn new_from_path_with_options(
+        filepath: impl AsRef<Path>,
+        options: FiemapOptions,
+    ) -> Result<Fiemap> {
+        let file = File::open(filepath)?;
+
+        Ok(Self::new_with_options(file, options))
+    }
+
+    /// Creates a new [`Self`] from a file path, opening it with the
+    /// given [`OpenFlags`] instead of a plain [`File::open`]. Useful for
+    /// mass scans (e.g. the bundled `walkdir` examples) that shouldn't
+    /// bump every visited file's atime.
+    ///
+    /// `OpenFlags::NOATIME` degrades gracefully: if the open fails with
+    /// `EPERM` (the caller doesn't own the file and lacks `CAP_FOWNER`),
+    /// the open is retried without it rather than failing the scan.
+    pub fn new_from_path_with_flags(
+        filepath: impl AsRef<Path>,
+        flags: OpenFlags,
+    ) -> Result<Fiemap> {
+        Self::new_from_path_with_flags_and_options(filepath, flags, FiemapOptions::default())
+    }
+
+    /// Same as [`Self::new_from_path_with_flags`], but also accepts
+    /// [`FiemapOptions`] for the mapping itself.
+    pub fn new_from_path_with_flags_and_options(
+        filepath: impl AsRef<Path>,
+        flags: OpenFlags,
+        options: FiemapOptions,
+    ) -> Result<Fiemap> {
+        let file = Self::open_with_flags(filepath.as_ref(), flags)?;
+        Ok(Self::new_with_options(file, options))
+    }
+
+    fn open_with_flags(filepath: &Path, flags: OpenFlags) -> Result<File> {
+        let mut custom_flags = 0;
+        if flags.contains(OpenFlags::NOFOLLOW) {
+            custom_flags |= O_NOFOLLOW;
         }
 
-        let rc = unsafe { ioctl(self.fd, FS_IOC_FIEMAP, req as *mut _) };
-        if rc != 0 {
-            Err(Error::last_os_error())
-        } else {
-            self.cur_idx = 0;
-            self.size = req.fm_mapped_extents;
-            if req.fm_mapped_extents == 0
-                || req.fm_extents[req.fm_mapped_extents as usize - 1]
-                    .fe_flags
-                    .contains(FiemapExtentFlags::LAST)
+        if flags.contains(OpenFlags::NOATIME) {
+            match OpenOptions::new()
+                .read(true)
+                .custom_flags(custom_flags | O_NOATIME)
+                .open(filepath)
             {
+                Ok(file) => return Ok(file),
+                Err(e) if e.raw_os_error() == Some(EPERM) => {
+                    // Caller doesn't own the file / lacks CAP_FOWNER:
+                    // degrade gracefully instead of aborting the scan.
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(custom_flags)
+            .open(filepath)
+    }
+
+    /// Wraps `self` into a [`Segments`] iterator that also yields the
+    /// holes between (and around) the mapped extents, which is useful
+    /// for sparse-aware copying or `du --apparent`-style reporting.
+    ///
+    /// The file size is fetched via `fstat` so a trailing hole after the
+    /// last extent can be reported too, bounded by the requested
+    /// `start + length` range rather than the whole file, so a
+    /// [`FiemapOptions`]-bounded `Fiemap` doesn't report a trailing hole
+    /// past the sub-range it was asked to map.
+    pub fn segments(self) -> Result<Segments> {
+        let file_size = self._file.metadata()?.len();
+        let start = self.start;
+        let end = self.end.min(file_size);
+        Ok(Segments {
+            inner: self,
+            end,
+            prev_end: start,
+            pending: None,
+            ended: false,
+        })
+    }
+
+    /// Reports whether this [`Self`] fell back to the legacy `FIBMAP`
+    /// ioctl because `FS_IOC_FIEMAP` wasn't supported by the underlying
+    /// filesystem. When `true`, extent flags carry no information beyond
+    /// `LAST` on the final extent, since `FIBMAP` reports none.
+    pub fn used_fibmap_fallback(&self) -> bool {
+        matches!(self.backend, Backend::Fibmap(_))
+    }
+
+    fn get_extents(&mut self) -> Result<()> {
+        let is_first_call = self.size == 0;
+        let end = self.end;
+
+        let fallback_err = match &mut self.backend {
+            Backend::Fiemap(c_fiemap) => {
+                if !is_first_call {
+                    let last = c_fiemap.extents()[self.size as usize - 1];
+                    c_fiemap.header_mut().fm_start = last.fe_logical + last.fe_length;
+                }
+
+                let rc = unsafe { ioctl(self.fd, FS_IOC_FIEMAP, c_fiemap.as_mut_ptr()) };
+                if rc != 0 {
+                    let err = Error::last_os_error();
+                    match err.raw_os_error() {
+                        Some(ENOTTY) | Some(EOPNOTSUPP) if is_first_call => Some(err),
+                        _ => return Err(err),
+                    }
+                } else {
+                    self.cur_idx = 0;
+                    self.size = c_fiemap.header().fm_mapped_extents;
+                    if self.size == 0 {
+                        self.ended = true;
+                    } else {
+                        let last = c_fiemap.extents()[self.size as usize - 1];
+                        if last.fe_flags.contains(FiemapExtentFlags::LAST)
+                            || last.fe_logical >= end
+                        {
+                            self.ended = true;
+                        }
+                    }
+                    None
+                }
+            }
+            Backend::Fibmap(_) => {
                 self.ended = true;
+                None
             }
-            Ok(())
+        };
+
+        match fallback_err {
+            Some(_) => self.switch_to_fibmap(),
+            None => Ok(()),
         }
     }
+
+    fn switch_to_fibmap(&mut self) -> Result<()> {
+        let meta = self._file.metadata()?;
+        let blksize = meta.blksize().max(1);
+        let file_size = meta.len();
+        let block_count = file_size.div_ceil(blksize);
+        let start_block = self.start / blksize;
+
+        let mut extents: Vec<FiemapExtent> = Vec::new();
+        for logical_block in start_block..block_count {
+            let mut block = logical_block as c_int;
+            let rc = unsafe { ioctl(self.fd, FIBMAP, &mut block as *mut c_int) };
+            if rc != 0 {
+                return Err(Error::last_os_error());
+            }
+            if block == 0 {
+                // A physical block of 0 means this logical block is a hole.
+                continue;
+            }
+
+            let fe_logical = logical_block * blksize;
+            // `block` is the raw FIBMAP-returned block number, which is
+            // unsigned in truth; going through `u32` first avoids
+            // sign-extending it to near `u64::MAX` once the top bit is
+            // set (reachable once physical offsets exceed ~8TiB at 4K
+            // blocks, since FIBMAP's block number is only 32 bits wide).
+            let fe_physical = (block as u32) as u64 * blksize;
+            if let Some(last) = extents.last_mut() {
+                if last.fe_physical + last.fe_length == fe_physical
+                    && last.fe_logical + last.fe_length == fe_logical
+                {
+                    last.fe_length += blksize;
+                    continue;
+                }
+            }
+            extents.push(FiemapExtent {
+                fe_logical,
+                fe_physical,
+                fe_length: blksize,
+                fe_reserved64: [0; 2],
+                fe_flags: FiemapExtentFlags::empty(),
+                fe_reserved: [0; 3],
+            });
+        }
+        if let Some(last) = extents.last_mut() {
+            last.fe_flags |= FiemapExtentFlags::LAST;
+        }
+
+        self.cur_idx = 0;
+        self.size = extents.len() as u32;
+        self.ended = true;
+        self.backend = Backend::Fibmap(extents);
+        Ok(())
+    }
 }
 
 impl Iterator for Fiemap {
@@ -107,33 +418,142 @@ impl Iterator for Fiemap {
 
         let idx = self.cur_idx;
         self.cur_idx += 1;
-        Some(Ok(self.fiemap.fm_extents[idx]))
+        let extent = match &self.backend {
+            Backend::Fiemap(c_fiemap) => c_fiemap.extents()[idx],
+            Backend::Fibmap(extents) => extents[idx],
+        };
+        if extent.fe_logical >= self.end {
+            self.ended = true;
+            self.cur_idx = self.size as usize;
+            return None;
+        }
+        Some(Ok(extent))
     }
 }
 
+/// A single piece of a file's logical layout, as yielded by [`Segments`].
+#[derive(Debug, Copy, Clone)]
+pub enum Segment {
+    /// A mapped extent, as yielded by [`Fiemap`] (from `FS_IOC_FIEMAP`,
+    /// or synthesized from `FIBMAP` when the fallback is in use).
+    Data(FiemapExtent),
+    /// A gap between extents (or before the first / after the last one)
+    /// that holds no data, i.e. reads as zeroes.
+    Hole { logical: u64, length: u64 },
+}
+
+/// Hole-aware wrapper around [`Fiemap`], see [`Fiemap::segments`].
 #[derive(Debug)]
+pub struct Segments {
+    inner: Fiemap,
+    end: u64,
+    prev_end: u64,
+    pending: Option<Segment>,
+    ended: bool,
+}
+
+impl Iterator for Segments {
+    type Item = Result<Segment>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(segment) = self.pending.take() {
+            return Some(Ok(segment));
+        }
+
+        if self.ended {
+            return None;
+        }
+
+        match self.inner.next() {
+            Some(Ok(extent)) => {
+                let hole_start = self.prev_end;
+                self.prev_end = extent.fe_logical + extent.fe_length;
+                if extent.fe_logical > hole_start {
+                    self.pending = Some(Segment::Data(extent));
+                    Some(Ok(Segment::Hole {
+                        logical: hole_start,
+                        length: extent.fe_logical - hole_start,
+                    }))
+                } else {
+                    Some(Ok(Segment::Data(extent)))
+                }
+            }
+            Some(Err(e)) => {
+                self.ended = true;
+                Some(Err(e))
+            }
+            None => {
+                self.ended = true;
+                if self.prev_end < self.end {
+                    let hole = Segment::Hole {
+                        logical: self.prev_end,
+                        length: self.end - self.prev_end,
+                    };
+                    self.prev_end = self.end;
+                    Some(Ok(hole))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
 #[repr(C)]
-struct C_fiemap {
+struct FiemapHeader {
     fm_start: u64,
     fm_length: u64,
     fm_flags: u32,
     fm_mapped_extents: u32,
     fm_extent_count: u32,
     fm_reserved: u32,
-    fm_extents: [FiemapExtent; PAGESIZE],
+}
+
+/// Owns the single contiguous allocation the kernel expects: the
+/// 32-byte `struct fiemap` header immediately followed by
+/// `fm_extent_count` `struct fiemap_extent` entries. Backed by a
+/// `Vec<u64>` so the buffer is 8-byte aligned, matching the alignment
+/// of both [`FiemapHeader`] and [`FiemapExtent`].
+#[derive(Debug)]
+struct C_fiemap {
+    buf: Vec<u64>,
+    extent_count: u32,
 }
 
 impl C_fiemap {
-    fn new() -> Self {
-        Self {
-            fm_start: 0,
-            fm_length: u64::MAX,
-            fm_flags: 0,
+    fn new(flags: FiemapFlags, start: u64, length: u64, extent_count: u32) -> Self {
+        let header_words = size_of::<FiemapHeader>() / size_of::<u64>();
+        let extent_words = size_of::<FiemapExtent>() / size_of::<u64>();
+        let total_words = header_words + extent_count as usize * extent_words;
+
+        let mut buf = vec![0u64; total_words];
+        *unsafe { &mut *(buf.as_mut_ptr() as *mut FiemapHeader) } = FiemapHeader {
+            fm_start: start,
+            fm_length: length,
+            fm_flags: flags.bits(),
             fm_mapped_extents: 0,
-            fm_extent_count: PAGESIZE as u32,
+            fm_extent_count: extent_count,
             fm_reserved: 0,
-            fm_extents: [FiemapExtent::new(); PAGESIZE],
-        }
+        };
+
+        Self { buf, extent_count }
+    }
+
+    fn header(&self) -> &FiemapHeader {
+        unsafe { &*(self.buf.as_ptr() as *const FiemapHeader) }
+    }
+
+    fn header_mut(&mut self) -> &mut FiemapHeader {
+        unsafe { &mut *(self.buf.as_mut_ptr() as *mut FiemapHeader) }
+    }
+
+    fn extents(&self) -> &[FiemapExtent] {
+        let header_words = size_of::<FiemapHeader>() / size_of::<u64>();
+        let ptr = unsafe { self.buf.as_ptr().add(header_words) as *const FiemapExtent };
+        unsafe { std::slice::from_raw_parts(ptr, self.extent_count as usize) }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u64 {
+        self.buf.as_mut_ptr()
     }
 }
 
@@ -149,13 +569,18 @@ pub struct FiemapExtent {
 }
 
 impl FiemapExtent {
-    fn new() -> Self {
+    pub(crate) fn from_raw_parts(
+        fe_logical: u64,
+        fe_physical: u64,
+        fe_length: u64,
+        fe_flags: FiemapExtentFlags,
+    ) -> Self {
         Self {
-            fe_logical: 0,
-            fe_physical: 0,
-            fe_length: 0,
+            fe_logical,
+            fe_physical,
+            fe_length,
             fe_reserved64: [0; 2],
-            fe_flags: FiemapExtentFlags::empty(),
+            fe_flags,
             fe_reserved: [0; 3],
         }
     }
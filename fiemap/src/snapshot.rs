@@ -0,0 +1,199 @@
+//! Versioned binary snapshots of a file's extent map, so a layout can be
+//! cached and later diffed to detect fragmentation growth or relocation
+//! over time.
+
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+use crate::{FiemapExtent, FiemapExtentFlags};
+
+const MAGIC: u32 = 0x4d58_4546; // "FEXM", read as a little-endian u32.
+const FORMAT_VERSION: u32 = 2;
+
+/// A file's full extent list, along with enough metadata (path, size) to
+/// make sense of it later, plus a compact encode/decode pair for
+/// on-disk caching.
+#[derive(Debug, Clone)]
+pub struct FileExtentMap {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub extents: Vec<FiemapExtent>,
+}
+
+impl FileExtentMap {
+    /// Encodes `self` into the version-2 on-disk record format: a small
+    /// header (magic, format version, extent count), followed by the
+    /// file size and path, followed by one fixed-width little-endian
+    /// record per extent (`fe_logical`, `fe_physical`, `fe_length` as
+    /// `u64`, and the raw `u32` bits of `fe_flags`).
+    pub fn encode(&self) -> Vec<u8> {
+        let path_bytes = self.path.to_string_lossy().into_owned().into_bytes();
+
+        let mut buf = Vec::with_capacity(
+            4 + 4 + 4 + 8 + 4 + path_bytes.len() + self.extents.len() * EXTENT_RECORD_LEN,
+        );
+        buf.extend_from_slice(&MAGIC.to_le_bytes());
+        buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf.extend_from_slice(&(self.extents.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.file_size.to_le_bytes());
+        buf.extend_from_slice(&(path_bytes.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&path_bytes);
+
+        for extent in &self.extents {
+            buf.extend_from_slice(&extent.fe_logical.to_le_bytes());
+            buf.extend_from_slice(&extent.fe_physical.to_le_bytes());
+            buf.extend_from_slice(&extent.fe_length.to_le_bytes());
+            buf.extend_from_slice(&extent.fe_flags.bits().to_le_bytes());
+        }
+
+        buf
+    }
+
+    /// Decodes a snapshot previously produced by [`Self::encode`].
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes);
+
+        let magic = reader.read_u32()?;
+        if magic != MAGIC {
+            return Err(invalid_data("bad magic in extent map snapshot"));
+        }
+        let version = reader.read_u32()?;
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported extent map snapshot version {version}"
+            )));
+        }
+
+        let extent_count = reader.read_u32()? as usize;
+        let file_size = reader.read_u64()?;
+        let path_len = reader.read_u32()? as usize;
+        let path = String::from_utf8(reader.read_bytes(path_len)?.to_vec())
+            .map_err(|_| invalid_data("extent map snapshot path is not valid UTF-8"))?
+            .into();
+
+        // Validate the claimed extent count against what's actually left
+        // in the buffer before allocating for it: a truncated/corrupted
+        // snapshot can otherwise claim billions of extents and trigger
+        // a huge up-front allocation (or a capacity overflow) well
+        // before the per-field truncation checks below would catch it.
+        let max_extents = reader.remaining() / EXTENT_RECORD_LEN;
+        if extent_count > max_extents {
+            return Err(invalid_data("extent map snapshot is truncated"));
+        }
+
+        let mut extents = Vec::with_capacity(extent_count);
+        for _ in 0..extent_count {
+            let fe_logical = reader.read_u64()?;
+            let fe_physical = reader.read_u64()?;
+            let fe_length = reader.read_u64()?;
+            let fe_flags = FiemapExtentFlags::from_bits_truncate(reader.read_u32()?);
+            extents.push(FiemapExtent::from_raw_parts(
+                fe_logical, fe_physical, fe_length, fe_flags,
+            ));
+        }
+
+        Ok(Self {
+            path,
+            file_size,
+            extents,
+        })
+    }
+}
+
+/// One difference between an old and a new [`FileExtentMap`] for the
+/// same logical region, as reported by [`diff`].
+#[derive(Debug, Copy, Clone)]
+pub enum ExtentChange {
+    /// An extent present in `new` but not in `old`.
+    Added(FiemapExtent),
+    /// An extent present in `old` but not in `new`.
+    Removed(FiemapExtent),
+    /// An extent at the same `fe_logical` in both, but relocated
+    /// (`fe_physical`/`fe_length` differ) or with different flags.
+    Changed {
+        old: FiemapExtent,
+        new: FiemapExtent,
+    },
+}
+
+/// Pairs extents of `old` and `new` by `fe_logical` and reports which
+/// logical regions changed physical location or flags, letting a
+/// monitoring job detect fragmentation growth or relocation across
+/// snapshots without re-reading the whole file.
+pub fn diff(old: &FileExtentMap, new: &FileExtentMap) -> Vec<ExtentChange> {
+    use std::collections::BTreeMap;
+
+    let old_by_logical: BTreeMap<u64, FiemapExtent> =
+        old.extents.iter().map(|e| (e.fe_logical, *e)).collect();
+    let new_by_logical: BTreeMap<u64, FiemapExtent> =
+        new.extents.iter().map(|e| (e.fe_logical, *e)).collect();
+
+    let mut changes = Vec::new();
+    for (logical, old_extent) in &old_by_logical {
+        match new_by_logical.get(logical) {
+            None => changes.push(ExtentChange::Removed(*old_extent)),
+            Some(new_extent) => {
+                if new_extent.fe_physical != old_extent.fe_physical
+                    || new_extent.fe_length != old_extent.fe_length
+                    || new_extent.fe_flags.bits() != old_extent.fe_flags.bits()
+                {
+                    changes.push(ExtentChange::Changed {
+                        old: *old_extent,
+                        new: *new_extent,
+                    });
+                }
+            }
+        }
+    }
+    for (logical, new_extent) in &new_by_logical {
+        if !old_by_logical.contains_key(logical) {
+            changes.push(ExtentChange::Added(*new_extent));
+        }
+    }
+
+    changes
+}
+
+const EXTENT_RECORD_LEN: usize = 8 + 8 + 8 + 4;
+
+fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Small cursor over a byte slice, used to decode the fixed-width
+/// little-endian fields of a snapshot without manual offset bookkeeping.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| invalid_data("extent map snapshot is truncated"))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+}
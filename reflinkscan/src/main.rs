@@ -0,0 +1,163 @@
+use std::env::args;
+use std::fmt::Display;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+
+use fiemap::FiemapExtentFlags;
+use walkdir::WalkDir;
+
+/// A single extent as seen from one file, reduced to just the physical
+/// range and path we need for the overlap analysis below.
+struct ExtentRef {
+    physical_start: u64,
+    physical_end: u64,
+    shared_flag: bool,
+    path: PathBuf,
+}
+
+/// A run of physical storage shared (or suspected shared) between two or
+/// more files, built by merging overlapping [`ExtentRef`]s.
+///
+/// Member extents aren't necessarily coincident -- e.g. file A's
+/// `[0, 100)` and file B's `[50, 150)` merge into one region spanning
+/// `[0, 150)` even though only `[50, 100)` is actually duplicated -- so
+/// the region keeps every member extent's own range to compute
+/// reclaimable space precisely instead of assuming full coverage.
+struct SharedRegion {
+    physical_start: u64,
+    physical_end: u64,
+    confirmed_shared: bool,
+    paths: Vec<PathBuf>,
+    member_ranges: Vec<(u64, u64)>,
+}
+
+impl SharedRegion {
+    fn len(&self) -> u64 {
+        self.physical_end - self.physical_start
+    }
+
+    /// Bytes that could be reclaimed if all but one copy were removed,
+    /// computed from how many member extents actually cover each byte
+    /// rather than assuming every member spans the whole region.
+    fn reclaimable(&self) -> u64 {
+        let mut boundaries: Vec<(u64, i64)> = Vec::with_capacity(self.member_ranges.len() * 2);
+        for &(start, end) in &self.member_ranges {
+            boundaries.push((start, 1));
+            boundaries.push((end, -1));
+        }
+        boundaries.sort_unstable();
+
+        let mut reclaimable = 0u64;
+        let mut coverage: i64 = 0;
+        let mut prev = self.physical_start;
+        for (pos, delta) in boundaries {
+            if pos > prev && coverage > 1 {
+                reclaimable += (coverage as u64 - 1) * (pos - prev);
+            }
+            prev = pos;
+            coverage += delta;
+        }
+        reclaimable
+    }
+}
+
+fn collect_extents(entry: &walkdir::DirEntry, extents: &mut Vec<ExtentRef>) -> Result<(), Error> {
+    if !entry.file_type().is_file() {
+        return Ok(());
+    }
+
+    for fe in fiemap::fiemap(entry.path())? {
+        let fe = fe?;
+        extents.push(ExtentRef {
+            physical_start: fe.fe_physical,
+            physical_end: fe.fe_physical + fe.fe_length,
+            shared_flag: fe.fe_flags.contains(FiemapExtentFlags::SHARED),
+            path: entry.path().to_path_buf(),
+        });
+    }
+
+    Ok(())
+}
+
+/// Groups extents whose physical ranges overlap into [`SharedRegion`]s.
+/// `extents` does not need to be pre-sorted.
+fn group_shared_regions(mut extents: Vec<ExtentRef>) -> Vec<SharedRegion> {
+    extents.sort_by_key(|e| e.physical_start);
+
+    let mut regions: Vec<SharedRegion> = Vec::new();
+    for extent in extents {
+        if let Some(region) = regions.last_mut() {
+            if extent.physical_start < region.physical_end {
+                region.physical_end = region.physical_end.max(extent.physical_end);
+                region.confirmed_shared |= extent.shared_flag;
+                if !region.paths.contains(&extent.path) {
+                    region.paths.push(extent.path);
+                }
+                region
+                    .member_ranges
+                    .push((extent.physical_start, extent.physical_end));
+                continue;
+            }
+        }
+
+        regions.push(SharedRegion {
+            physical_start: extent.physical_start,
+            physical_end: extent.physical_end,
+            confirmed_shared: extent.shared_flag,
+            paths: vec![extent.path],
+            member_ranges: vec![(extent.physical_start, extent.physical_end)],
+        });
+    }
+
+    regions.retain(|region| region.paths.len() > 1);
+    regions
+}
+
+fn process<P: AsRef<Path> + Display>(dir: P, extents: &mut Vec<ExtentRef>) {
+    for entry in WalkDir::new(dir.as_ref()).same_file_system(true) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("{}: Error {:?}", dir, e);
+                continue;
+            }
+        };
+        if let Err(e) = collect_extents(&entry, extents) {
+            eprintln!("{}: Error {:?}", entry.path().display(), e);
+        }
+    }
+}
+
+fn main() {
+    let mut extents = Vec::new();
+    for path in args().skip(1) {
+        process(path, &mut extents);
+    }
+
+    let regions = group_shared_regions(extents);
+    let mut total_reclaimable = 0;
+    for region in &regions {
+        total_reclaimable += region.reclaimable();
+        println!(
+            "physical [{}, {}) ({} bytes){}:",
+            region.physical_start,
+            region.physical_end,
+            region.len(),
+            if region.confirmed_shared {
+                ""
+            } else {
+                " -- overlap without SHARED flag, consistency warning"
+            }
+        );
+        for path in &region.paths {
+            println!("  {}", path.display());
+        }
+        println!("  reclaimable: {} bytes", region.reclaimable());
+    }
+
+    println!(
+        "{} shared region(s), {} bytes reclaimable total",
+        regions.len(),
+        total_reclaimable
+    );
+}